@@ -2,18 +2,22 @@ use clap::{App, Arg, AppSettings};
 use nix::unistd;
 
 use std::borrow::Cow;
+use std::fs;
 use std::ffi::{OsStr, OsString};
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
 use std::iter;
+use std::ops::RangeInclusive;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::result::Result as StdResult;
+use std::thread;
 
 use ::{UtilData, UtilRead, UtilWrite};
 use super::UtilSetup;
 use super::ast::ExitCode;
 use super::command::{ExecData, InProcessCommand};
 use super::env::{EnvFd, Environment};
-use super::error::{CmdResult, BuiltinError, CommandError};
+use super::error::{CmdResult, BuiltinError, CommandError, ShellControlFlow};
 use super::option::ShellOption;
 
 type Result<T> = StdResult<T, BuiltinError>;
@@ -25,6 +29,10 @@ pub struct BuiltinSet {
 
 impl BuiltinSet {
     pub fn new(options: Vec<ShellOption>) -> Self {
+        // raise the soft open-file limit here, once, as part of the same setup path that builds
+        // the shell's Environment, so pipelines spawning many children don't exhaust it
+        let _ = raise_fd_limit();
+
         Self {
             options: options,
         }
@@ -38,6 +46,7 @@ impl BuiltinSet {
             "exit" => Builtin::Exit(ExitBuiltin),
             "export" => Builtin::Export(ExportBuiltin),
             "read" => Builtin::Read(ReadBuiltin),
+            "ulimit" => Builtin::Ulimit(UlimitBuiltin),
             "unset" => Builtin::Unset(UnsetBuiltin),
             _ => return None
         })
@@ -49,6 +58,7 @@ pub enum Builtin {
     Exit(ExitBuiltin),
     Export(ExportBuiltin),
     Read(ReadBuiltin),
+    Ulimit(UlimitBuiltin),
     Unset(UnsetBuiltin),
 }
 
@@ -103,6 +113,7 @@ impl Builtin {
             Exit(u) => u.run(setup, env, data),
             Export(u) => u.run(setup, env, data),
             Read(u) => u.run(setup, env, data),
+            Ulimit(u) => u.run(setup, env, data),
             Unset(u) => u.run(setup, env, data),
         }.map_err(|e| CommandError::Builtin(e))
     }
@@ -120,16 +131,19 @@ impl InProcessCommand for Builtin {
             _ => unimplemented!(),
         };
 
-        Ok(match res {
-            Ok(m) => m,
+        match res {
+            Ok(m) => Ok(m),
+            // `exit` needs to unwind all the way out of the shell rather than being reported as
+            // an ordinary command failure, so let it keep propagating past this point
+            Err(CommandError::Builtin(BuiltinError::ControlFlow(cf))) => Err(CommandError::ControlFlow(cf)),
             Err(f) => {
                 // XXX: do we really want to ignore write errors?
                 // FIXME: should probably not write to setup.error() unless we create a new
                 //        UtilData struct each time we call a builtin
                 let _ = writeln!(setup.error(), "{}", f);
-                1
+                Ok(1)
             }
-        })
+        }
     }
 }
 
@@ -139,10 +153,38 @@ trait BuiltinSetup {
 
 pub struct ExecBuiltin;
 
+impl ExecBuiltin {
+    // fds 0-2 are handled separately by the caller (they go through stdin()/stdout()/stderr()
+    // rather than the pre_exec dup2 loop in run()), so we only need to carry over 3-9 here,
+    // mirroring what command.rs does for external commands
+    const EXTRA_FDS: RangeInclusive<RawFd> = 3..=9;
+
+    // heredocs and other in-memory fd backings (EnvFd::Piped) have no real descriptor to hand to
+    // the child, so materialize them into an anonymous pipe filled with the buffered data
+    fn dup_env_fd(env: &mut Environment, fd_num: RawFd) -> Result<Option<RawFd>> {
+        use self::EnvFd::*;
+
+        Ok(match env.get_fd(fd_num).current_val().try_clone()? {
+            File(file) => Some(unistd::dup(file.as_raw_fd())?),
+            Fd(fd) => Some(unistd::dup(fd.as_raw_fd())?),
+            Piped(data) => {
+                let (read_end, write_end) = unistd::pipe()?;
+                // fill the pipe from a background thread rather than blocking here: a heredoc
+                // bigger than one pipe buffer (64KB on Linux) would otherwise deadlock the shell,
+                // since nothing reads from read_end until the child is spawned below
+                thread::spawn(move || {
+                    let mut write_end = unsafe { fs::File::from_raw_fd(write_end) };
+                    let _ = write_end.write_all(&data);
+                });
+                Some(read_end)
+            }
+            Null => None,
+        })
+    }
+}
+
 // XXX: given that this replaces the current process, if we are being used as a library the calling
 //      process will be replaced.  this could be an issue when e.g. running our tests
-// TODO: because this needs to affect the "current shell execution environment," we need to somehow
-//       return the fds to the parent environment
 impl BuiltinSetup for ExecBuiltin {
     fn run<S>(&self, setup: &mut S, env: &mut Environment, data: ExecData) -> Result<ExitCode>
     where
@@ -150,24 +192,19 @@ impl BuiltinSetup for ExecBuiltin {
     {
         use std::process::{Command, Stdio};
         use std::os::unix::io::FromRawFd;
-        use std::os::unix::process::CommandExt;
+        use std::os::unix::process::{CommandExt, ExitStatusExt};
 
         let mut args = data.args.into_iter();
         if let Some(name) = args.next() {
-            // replace the current process with that started by the given command
+            // replace the current process with that started by the given command (unless
+            // ShellOption::NoExec says this shell is embedded as a library and must survive)
             let mut cmd = Command::new(name);
             cmd.args(args)
                 .env_clear()
                 .envs(env.export_iter())
                 .envs(data.env.iter());
 
-            // TODO: figure out what to do if one of the IO interfaces doesn't have a file
-            //       descriptor (such as as Vec<u8>).  afaict this is only really an issue with
-            //       heredocs and when we are called as a library from a process that most likely
-            //       does not actually want to be replaced
             // NOTE: we need to duplicate the fds as from_raw_fd() takes ownership
-            // TODO: this needs to duplicate all the fds (3-9 because stdin/stdout/stderr are done
-            //       already below) like in command.rs
             if let Some(fd) = setup.input().raw_fd() {
                 let fd = unistd::dup(fd)?;
                 cmd.stdin(unsafe { Stdio::from_raw_fd(fd) });
@@ -181,8 +218,64 @@ impl BuiltinSetup for ExecBuiltin {
                 cmd.stderr(unsafe { Stdio::from_raw_fd(fd) });
             }
 
-            // if this actually returns an error the process failed to start
-            Err(cmd.exec().into())
+            let mut extra_fds = vec![];
+            for fd_num in Self::EXTRA_FDS {
+                if let Some(fd) = Self::dup_env_fd(env, fd_num)? {
+                    extra_fds.push((fd_num, fd));
+                }
+            }
+            // the pre_exec closure takes ownership of its own copy of the list; the original stays
+            // behind so that, when spawning (ShellOption::NoExec), the parent can close its copies
+            // once the child is gone
+            let extra_fds_for_child = extra_fds.clone();
+            if !extra_fds_for_child.is_empty() {
+                // std::process::Command only has first-class support for fds 0-2, so the rest
+                // are wired up by hand right before the child execs
+                unsafe {
+                    cmd.pre_exec(move || {
+                        for &(fd_num, fd) in &extra_fds_for_child {
+                            if libc::dup2(fd, fd_num) < 0 {
+                                return Err(io::Error::last_os_error());
+                            }
+                            // the dup2 source is no longer needed in the child once it's been
+                            // copied into its target slot
+                            if fd != fd_num {
+                                libc::close(fd);
+                            }
+                        }
+                        Ok(())
+                    });
+                }
+            }
+
+            if env.has_option(ShellOption::NoExec) {
+                // don't replace the current process; used when mesabox is embedded as a library
+                // and the calling process needs to keep running after `exec` returns
+                let result = (|| {
+                    let mut child = cmd.spawn()?;
+                    let status = child.wait()?;
+                    Ok(match status.code() {
+                        Some(code) => code as ExitCode,
+                        None => 128 + status.signal().unwrap_or(0) as ExitCode,
+                    })
+                })();
+
+                // spawn() forks, so pre_exec (and the fd cleanup inside it) ran only in the
+                // child's address space; the parent's own copies of the duplicated fds are still
+                // open here and would otherwise leak for as long as this shell process runs
+                for (_, fd) in extra_fds {
+                    let _ = unistd::close(fd);
+                }
+
+                result
+            } else {
+                // cmd.exec() never forks: pre_exec runs in this very process right before the
+                // exec syscall, so by the time this can return (only on failure) the duplicated
+                // fds have already been dup2'd into place and closed from inside pre_exec itself;
+                // closing them again here would risk stealing an fd opened by something else in
+                // the meantime, so there's nothing left to clean up
+                Err(cmd.exec().into())
+            }
         } else {
             Ok(0)
         }
@@ -191,30 +284,103 @@ impl BuiltinSetup for ExecBuiltin {
 
 pub struct ExitBuiltin;
 
+// `exit` doesn't return a normal exit code: it unwinds the whole shell execution environment, so
+// it reports itself via Err(BuiltinError::ControlFlow(..)) rather than Ok(..).  InProcessCommand::execute
+// recognizes that variant and lets it keep propagating instead of printing it as a command error;
+// from there it's on the AST executor to unwind command lists, pipelines, function bodies, and
+// subshells until it reaches the top-level run loop.
 impl BuiltinSetup for ExitBuiltin {
-    fn run<S: UtilSetup>(&self, _setup: &mut S, _env: &mut Environment, _data: ExecData) -> Result<ExitCode> {
-        // TODO: figure out how to exit properly
-        unimplemented!()
+    fn run<S: UtilSetup>(&self, _setup: &mut S, env: &mut Environment, data: ExecData) -> Result<ExitCode> {
+        let matches = App::new("exit")
+            .setting(AppSettings::NoBinaryName)
+            .arg(Arg::with_name("N")
+                .index(1)
+                .validator(|val| {
+                    val.parse::<i64>().map(|_| ()).map_err(|_| format!("{}: numeric argument required", val))
+                }))
+            .get_matches_from_safe(data.args);
+
+        let matches = match matches {
+            Ok(m) => m,
+            // POSIX: a non-numeric operand is an error in its own right, with status 2
+            Err(_) => return Err(BuiltinError::ControlFlow(ShellControlFlow::Exit(2))),
+        };
+
+        let status = match matches.value_of("N") {
+            Some(n) => n.parse::<i64>().expect("validated by clap"),
+            // POSIX: omitting the operand exits with the status of the last command
+            None => env.last_status() as i64,
+        };
+
+        Err(BuiltinError::ControlFlow(ShellControlFlow::Exit((status & 0xff) as ExitCode)))
     }
 }
 
 pub struct ExportBuiltin;
 
 impl BuiltinSetup for ExportBuiltin {
-    // TODO: needs to support -p option
-    fn run<S>(&self, _setup: &mut S, env: &mut Environment, data: ExecData) -> Result<ExitCode>
+    fn run<S>(&self, setup: &mut S, env: &mut Environment, data: ExecData) -> Result<ExitCode>
     where
         S: UtilSetup,
     {
-        // TODO: need to split args like VarAssign (we are just assuming names are given atm)
-        for arg in data.args {
-            env.export_var(Cow::Owned(arg));
+        let matches = App::new("export")
+            .setting(AppSettings::NoBinaryName)
+            .arg(Arg::with_name("print")
+                .short("p"))
+            .arg(Arg::with_name("NAMES")
+                .index(1)
+                .multiple(true))
+            .get_matches_from_safe(data.args)?;
+
+        let names = matches.values_of_os("NAMES");
+
+        if matches.is_present("print") && names.is_none() {
+            let output = setup.output();
+            for (name, value) in env.export_iter() {
+                output.write_all(b"export ")?;
+                output.write_all(name.as_bytes())?;
+                output.write_all(b"=")?;
+                write_shell_quoted(output, value.as_ref())?;
+                output.write_all(b"\n")?;
+            }
+            return Ok(0);
+        }
+
+        if let Some(names) = names {
+            for arg in names {
+                // split at the first '=' the same way VarAssign does, so a bare name keeps its
+                // old behavior of just marking an existing variable exported
+                let bytes = arg.as_bytes();
+                match bytes.iter().position(|&b| b == b'=') {
+                    Some(pos) => {
+                        let name = OsStr::from_bytes(&bytes[..pos]).to_owned();
+                        let value = OsStr::from_bytes(&bytes[pos + 1..]).to_owned();
+                        env.set_var(Cow::Owned(name.clone()), value);
+                        env.export_var(Cow::Owned(name));
+                    }
+                    None => env.export_var(Cow::Borrowed(arg)),
+                }
+            }
         }
 
         Ok(0)
     }
 }
 
+// writes `value` as a single-quoted, re-inputtable shell word (the POSIX-mandated form for
+// `export -p`), escaping embedded single quotes as '\''
+fn write_shell_quoted<W: Write>(w: &mut W, value: &OsStr) -> io::Result<()> {
+    w.write_all(b"'")?;
+    for &byte in value.as_bytes() {
+        if byte == b'\'' {
+            w.write_all(b"'\\''")?;
+        } else {
+            w.write_all(&[byte])?;
+        }
+    }
+    w.write_all(b"'")
+}
+
 pub struct UnsetBuiltin;
 
 impl BuiltinSetup for UnsetBuiltin {
@@ -253,60 +419,220 @@ impl BuiltinSetup for UnsetBuiltin {
     }
 }
 
+pub struct UlimitBuiltin;
+
+impl BuiltinSetup for UlimitBuiltin {
+    fn run<S>(&self, setup: &mut S, _env: &mut Environment, data: ExecData) -> Result<ExitCode>
+    where
+        S: UtilSetup,
+    {
+        use nix::sys::resource::{getrlimit, setrlimit, Resource};
+
+        // only -n (open files) is implemented for now; it's the limit that actually matters for
+        // spawning pipelines of child processes
+        let matches = App::new("ulimit")
+            .setting(AppSettings::NoBinaryName)
+            .arg(Arg::with_name("hard").short("H").overrides_with("soft"))
+            .arg(Arg::with_name("soft").short("S"))
+            .arg(Arg::with_name("nofiles").short("n"))
+            .arg(Arg::with_name("LIMIT")
+                .index(1)
+                .validator(|val| {
+                    if val == "unlimited" || val.parse::<u64>().is_ok() {
+                        Ok(())
+                    } else {
+                        Err(format!("{}: invalid number", val))
+                    }
+                }))
+            .get_matches_from_safe(data.args)?;
+
+        let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE)?;
+        let want_hard = matches.is_present("hard");
+
+        match matches.value_of("LIMIT") {
+            Some(limit) => {
+                let new_limit = if limit == "unlimited" {
+                    hard
+                } else {
+                    limit.parse::<u64>().expect("validated above")
+                };
+                let (new_soft, new_hard) = if want_hard {
+                    (soft, new_limit)
+                } else {
+                    (new_limit, hard)
+                };
+                setrlimit(Resource::RLIMIT_NOFILE, new_soft, new_hard)?;
+                Ok(0)
+            }
+            None => {
+                let current = if want_hard { hard } else { soft };
+                writeln!(setup.output(), "{}", current)?;
+                Ok(0)
+            }
+        }
+    }
+}
+
 pub struct ReadBuiltin;
 
+impl ReadBuiltin {
+    // -t: block until the fd is readable or the timeout elapses, using select() since that's
+    // available on every platform nix targets (poll() would work just as well here)
+    fn wait_for_input(fd: RawFd, timeout_secs: f64) -> Result<bool> {
+        use nix::sys::select::{select, FdSet};
+        use nix::sys::time::{TimeVal, TimeValLike};
+
+        let mut read_fds = FdSet::new();
+        read_fds.insert(fd);
+        let mut tv = TimeVal::milliseconds((timeout_secs * 1000.0) as i64);
+        let ready = select(fd + 1, Some(&mut read_fds), None, None, Some(&mut tv))?;
+        Ok(ready > 0)
+    }
+}
+
 impl BuiltinSetup for ReadBuiltin {
     fn run<S>(&self, setup: &mut S, env: &mut Environment, data: ExecData) -> Result<ExitCode>
     where
         S: UtilSetup,
     {
+        use nix::sys::termios::{self, LocalFlags, SetArg};
+
         let matches = App::new("read")
             .setting(AppSettings::NoBinaryName)
             // if present we treat backslash as a normal character rather than the start of an escape
             // sequence
             .arg(Arg::with_name("backslash")
                 .short("r"))
+            .arg(Arg::with_name("prompt")
+                .short("p")
+                .takes_value(true)
+                .value_name("PROMPT"))
+            .arg(Arg::with_name("timeout")
+                .short("t")
+                .takes_value(true)
+                .value_name("TIMEOUT")
+                .validator(|val| val.parse::<f64>().map(|_| ()).map_err(|_| format!("{}: invalid timeout", val))))
+            .arg(Arg::with_name("nchars")
+                .short("n")
+                .takes_value(true)
+                .value_name("NCHARS")
+                .validator(|val| val.parse::<usize>().map(|_| ()).map_err(|_| format!("{}: invalid number", val))))
+            .arg(Arg::with_name("nchars_noeof")
+                .short("N")
+                .takes_value(true)
+                .value_name("NCHARS")
+                .overrides_with("nchars")
+                .validator(|val| val.parse::<usize>().map(|_| ()).map_err(|_| format!("{}: invalid number", val))))
+            .arg(Arg::with_name("delim")
+                .short("d")
+                .takes_value(true)
+                .value_name("DELIM"))
+            .arg(Arg::with_name("silent")
+                .short("s"))
             .arg(Arg::with_name("VARS")
                 .index(1)
                 .multiple(true)
                 .required(true))
             .get_matches_from_safe(data.args)?;
 
-        let input = setup.input();
-        let mut input = input.lock_reader()?;
+        let raw_fd = setup.input().raw_fd();
+
+        if let Some(prompt) = matches.value_of_os("prompt") {
+            let is_tty = raw_fd.map(|fd| unistd::isatty(fd).unwrap_or(false)).unwrap_or(false);
+            if is_tty {
+                setup.error().write_all(prompt.as_bytes())?;
+                setup.error().flush()?;
+            }
+        }
 
         let ignore_backslash = matches.is_present("backslash");
+        let delim = matches.value_of_os("delim")
+            .and_then(|d| d.as_bytes().first().cloned())
+            .unwrap_or(b'\n');
+        let nchars = matches.value_of("nchars").or_else(|| matches.value_of("nchars_noeof"))
+            .map(|n| n.parse::<usize>().expect("validated by clap"));
+        let no_eof = matches.is_present("nchars_noeof");
+
+        if let (Some(fd), Some(timeout)) = (raw_fd, matches.value_of("timeout")) {
+            let timeout = timeout.parse::<f64>().expect("validated by clap");
+            if !Self::wait_for_input(fd, timeout)? {
+                // POSIX: report a timed-out read with a nonzero status rather than treating it as
+                // an error to be printed
+                return Ok(1);
+            }
+        }
 
-        let check_backslash = |buffer: &mut Vec<u8>| {
-            loop {
-                let res = match buffer.iter().last() {
-                    Some(b'\n') => {
-                        buffer.pop();
-                        continue;
+        // -s: turn off local echo for the duration of the read, restoring it afterward no matter
+        // how the read finishes
+        let saved_termios = if matches.is_present("silent") {
+            raw_fd.and_then(|fd| termios::tcgetattr(fd).ok().map(|termios| (fd, termios)))
+        } else {
+            None
+        };
+        if let Some((fd, ref termios)) = saved_termios {
+            let mut no_echo = termios.clone();
+            no_echo.local_flags.remove(LocalFlags::ECHO);
+            let _ = termios::tcsetattr(fd, SetArg::TCSANOW, &no_echo);
+        }
+
+        let read_result = (|| -> Result<Vec<u8>> {
+            let input = setup.input();
+            let mut input = input.lock_reader()?;
+
+            if let Some(n) = nchars {
+                // -n/-N: stop after a fixed number of bytes rather than a full line
+                let mut buffer = Vec::with_capacity(n);
+                let mut byte = [0u8; 1];
+                while buffer.len() < n {
+                    if input.read(&mut byte)? == 0 {
+                        break;
                     }
-                    Some(b'\\') => {
-                        // need to make sure this byte isn't escaped
-                        buffer.iter().rev().skip(1).take_while(|&&byte| byte == b'\\').count() % 2 == 1
+                    if !no_eof && byte[0] == delim {
+                        break;
                     }
-                    _ => true,
-                };
-                return res;
+                    buffer.push(byte[0]);
+                }
+                return Ok(buffer);
             }
-        };
 
-        let mut buffer = vec![];
-        loop {
-            // TODO: check for EOF
-            input.read_until(b'\n', &mut buffer)?;
-            let not_backslash = check_backslash(&mut buffer);
-            // TODO: handle heredoc portion?
-            if ignore_backslash || not_backslash {
-                break;
+            let check_backslash = |buffer: &mut Vec<u8>| {
+                loop {
+                    let res = match buffer.iter().last() {
+                        Some(&byte) if byte == delim => {
+                            buffer.pop();
+                            continue;
+                        }
+                        Some(b'\\') => {
+                            // need to make sure this byte isn't escaped
+                            buffer.iter().rev().skip(1).take_while(|&&byte| byte == b'\\').count() % 2 == 1
+                        }
+                        _ => true,
+                    };
+                    return res;
+                }
+            };
+
+            let mut buffer = vec![];
+            loop {
+                // TODO: check for EOF
+                input.read_until(delim, &mut buffer)?;
+                let not_backslash = check_backslash(&mut buffer);
+                // TODO: handle heredoc portion?
+                if ignore_backslash || not_backslash {
+                    break;
+                }
+                // we need to remove the backslash
+                buffer.pop();
             }
-            // we need to remove the backslash
-            buffer.pop();
+            Ok(buffer)
+        })();
+
+        if let Some((fd, termios)) = saved_termios {
+            let _ = termios::tcsetattr(fd, SetArg::TCSANOW, &termios);
         }
 
+        let buffer = read_result?;
+
         let vars = matches.values_of_os("VARS").unwrap();
         let var_count = vars.clone().count();
 
@@ -345,3 +671,56 @@ impl BuiltinSetup for ReadBuiltin {
         Ok(0)
     }
 }
+
+/// Raise the soft `RLIMIT_NOFILE` limit up to the hard limit.
+///
+/// A shell that launches many child processes in a pipeline can easily exhaust the default soft
+/// descriptor limit, so this should be called once from the same setup path that builds the
+/// `Environment`, before any pipelines are spawned.
+pub fn raise_fd_limit() -> nix::Result<()> {
+    use nix::sys::resource::{getrlimit, setrlimit, Resource};
+
+    let (soft, mut hard) = getrlimit(Resource::RLIMIT_NOFILE)?;
+
+    #[cfg(target_os = "macos")]
+    {
+        // macOS reports RLIM_INFINITY for the hard limit but silently enforces
+        // kern.maxfilesperproc, so setrlimit() fails unless we clamp to it ourselves first
+        if let Ok(max_files) = macos_max_files_per_proc() {
+            hard = hard.min(max_files);
+        }
+    }
+
+    if hard > soft {
+        setrlimit(Resource::RLIMIT_NOFILE, hard, hard)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn macos_max_files_per_proc() -> nix::Result<u64> {
+    use std::mem;
+    use std::ptr;
+
+    let mut name = [libc::CTL_KERN, libc::KERN_MAXFILESPERPROC];
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>();
+
+    let res = unsafe {
+        libc::sysctl(
+            name.as_mut_ptr(),
+            name.len() as libc::c_uint,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    };
+
+    if res == 0 {
+        Ok(value as u64)
+    } else {
+        Err(nix::Error::Sys(nix::errno::Errno::last()))
+    }
+}
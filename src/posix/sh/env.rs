@@ -0,0 +1,124 @@
+//
+// Copyright (c) 2018, The MesaLock Linux Project Contributors
+// All rights reserved.
+//
+// This work is licensed under the terms of the BSD 3-Clause License.
+// For a copy, see the LICENSE file.
+//
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use super::ast::ExitCode;
+use super::option::ShellOption;
+
+/// One of the shell's open file descriptor slots (0 = stdin, 1 = stdout, 2 = stderr, and
+/// whatever else a redirection has opened).
+pub enum EnvFd {
+    /// A real, already-open file.
+    File(fs::File),
+    /// A file descriptor duplicated in from elsewhere (e.g. inherited from the parent process).
+    Fd(fs::File),
+    /// An in-memory backing with no real descriptor, such as a heredoc body.
+    Piped(Vec<u8>),
+    /// `/dev/null`-like: reads return EOF, writes are discarded.
+    Null,
+}
+
+impl EnvFd {
+    pub fn try_clone(&self) -> io::Result<EnvFd> {
+        Ok(match self {
+            EnvFd::File(f) => EnvFd::File(f.try_clone()?),
+            EnvFd::Fd(f) => EnvFd::Fd(f.try_clone()?),
+            EnvFd::Piped(data) => EnvFd::Piped(data.clone()),
+            EnvFd::Null => EnvFd::Null,
+        })
+    }
+}
+
+/// A single fd slot, tracked as a stack so a redirection can be undone once its scope ends.
+pub struct FdSlot {
+    stack: Vec<EnvFd>,
+}
+
+impl FdSlot {
+    pub fn current_val(&self) -> &EnvFd {
+        self.stack.last().expect("an fd slot should never be empty")
+    }
+}
+
+/// The shell's execution environment: variables, exported names, the open fd table, functions,
+/// and the `ShellOption`s active for this invocation.
+pub struct Environment {
+    fds: HashMap<RawFd, FdSlot>,
+    vars: HashMap<OsString, OsString>,
+    exported: HashSet<OsString>,
+    funcs: HashSet<OsString>,
+    options: Vec<ShellOption>,
+    last_status: ExitCode,
+}
+
+impl Environment {
+    pub fn new(options: Vec<ShellOption>) -> Self {
+        let mut fds = HashMap::new();
+        for fd_num in 0..3 {
+            fds.insert(fd_num, FdSlot { stack: vec![EnvFd::Null] });
+        }
+
+        Environment {
+            fds,
+            vars: HashMap::new(),
+            exported: HashSet::new(),
+            funcs: HashSet::new(),
+            options,
+            last_status: 0,
+        }
+    }
+
+    pub fn get_fd(&mut self, fd_num: RawFd) -> &mut FdSlot {
+        self.fds.entry(fd_num).or_insert_with(|| FdSlot { stack: vec![EnvFd::Null] })
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&OsString> {
+        self.vars.get(OsStr::new(name))
+    }
+
+    pub fn set_var(&mut self, name: Cow<OsStr>, value: OsString) {
+        self.vars.insert(name.into_owned(), value);
+    }
+
+    pub fn export_var(&mut self, name: Cow<OsStr>) {
+        self.exported.insert(name.into_owned());
+    }
+
+    pub fn export_iter<'a>(&'a self) -> impl Iterator<Item = (&'a OsStr, &'a OsStr)> + 'a {
+        self.exported.iter().filter_map(move |name| {
+            self.vars.get(name.as_os_str()).map(|value| (name.as_os_str(), value.as_os_str()))
+        })
+    }
+
+    pub fn remove_var(&mut self, name: &OsStr) {
+        self.vars.remove(name);
+        self.exported.remove(name);
+    }
+
+    pub fn remove_func(&mut self, name: &OsStr) {
+        self.funcs.remove(name);
+    }
+
+    pub fn has_option(&self, option: ShellOption) -> bool {
+        self.options.contains(&option)
+    }
+
+    pub fn last_status(&self) -> ExitCode {
+        self.last_status
+    }
+
+    pub fn set_last_status(&mut self, status: ExitCode) {
+        self.last_status = status;
+    }
+}
@@ -0,0 +1,93 @@
+//
+// Copyright (c) 2018, The MesaLock Linux Project Contributors
+// All rights reserved.
+//
+// This work is licensed under the terms of the BSD 3-Clause License.
+// For a copy, see the LICENSE file.
+//
+
+use clap;
+use nix;
+
+use std::fmt;
+use std::io;
+use std::result::Result as StdResult;
+
+use super::ast::ExitCode;
+
+pub type CmdResult<T> = StdResult<T, CommandError>;
+
+/// Non-local control flow that needs to unwind out of the shell's normal command execution
+/// instead of being reported as an ordinary failure. The AST executor is responsible for
+/// recognizing this and propagating it up through command lists, pipelines, function bodies, and
+/// subshells until it reaches the top-level run loop.
+#[derive(Debug)]
+pub enum ShellControlFlow {
+    /// `exit [n]`: terminate the whole shell execution environment with the given status.
+    Exit(ExitCode),
+}
+
+/// Errors that can occur while running a builtin.
+#[derive(Debug)]
+pub enum BuiltinError {
+    Io(io::Error),
+    Nix(nix::Error),
+    Clap(clap::Error),
+    /// Not really a failure: a builtin (e.g. `exit`) asking to unwind past the current command.
+    ControlFlow(ShellControlFlow),
+}
+
+impl fmt::Display for BuiltinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuiltinError::Io(e) => write!(f, "{}", e),
+            BuiltinError::Nix(e) => write!(f, "{}", e),
+            BuiltinError::Clap(e) => write!(f, "{}", e),
+            // control flow is handled by the executor before it would ever be displayed
+            BuiltinError::ControlFlow(_) => Ok(()),
+        }
+    }
+}
+
+impl From<io::Error> for BuiltinError {
+    fn from(err: io::Error) -> Self {
+        BuiltinError::Io(err)
+    }
+}
+
+impl From<nix::Error> for BuiltinError {
+    fn from(err: nix::Error) -> Self {
+        BuiltinError::Nix(err)
+    }
+}
+
+impl From<clap::Error> for BuiltinError {
+    fn from(err: clap::Error) -> Self {
+        BuiltinError::Clap(err)
+    }
+}
+
+/// Errors that can occur while running any command, builtin or external.
+#[derive(Debug)]
+pub enum CommandError {
+    Builtin(BuiltinError),
+    Io(io::Error),
+    /// Not really a failure: unwinding past this command (e.g. because of `exit`).
+    ControlFlow(ShellControlFlow),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Builtin(e) => write!(f, "{}", e),
+            CommandError::Io(e) => write!(f, "{}", e),
+            CommandError::ControlFlow(_) => Ok(()),
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> Self {
+        CommandError::Io(err)
+    }
+}
@@ -0,0 +1,17 @@
+//
+// Copyright (c) 2018, The MesaLock Linux Project Contributors
+// All rights reserved.
+//
+// This work is licensed under the terms of the BSD 3-Clause License.
+// For a copy, see the LICENSE file.
+//
+
+/// Options that toggle shell behavior for the current invocation (mesabox's analogue of `set
+/// -o`/`set +o`, plus its own extensions for library embedding).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShellOption {
+    /// Don't replace the current process when running `exec`; spawn the command and wait on it
+    /// instead. Needed when mesabox is embedded as a library, since the host process must
+    /// survive a script's `exec` call.
+    NoExec,
+}
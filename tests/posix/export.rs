@@ -0,0 +1,30 @@
+//
+// Copyright (c) 2018, The MesaLock Linux Project Contributors
+// All rights reserved.
+//
+// This work is licensed under the terms of the BSD 3-Clause License.
+// For a copy, see the LICENSE file.
+//
+
+use assert_cmd::prelude::*;
+use std::process::Command;
+
+const NAME: &str = "sh";
+
+#[test]
+fn test_assign_and_export() {
+    new_cmd!()
+        .args(&["-c", "export FOO=bar; echo \"$FOO\""])
+        .assert()
+        .success()
+        .stdout("bar\n");
+}
+
+#[test]
+fn test_print() {
+    new_cmd!()
+        .args(&["-c", "export FOO=bar; export -p"])
+        .assert()
+        .success()
+        .stdout("export FOO='bar'\n");
+}
@@ -0,0 +1,42 @@
+//
+// Copyright (c) 2018, The MesaLock Linux Project Contributors
+// All rights reserved.
+//
+// This work is licensed under the terms of the BSD 3-Clause License.
+// For a copy, see the LICENSE file.
+//
+
+use assert_cmd::prelude::*;
+use std::process::Command;
+
+const NAME: &str = "sh";
+
+#[test]
+fn test_delim() {
+    new_cmd!()
+        .args(&["-c", "read -d : var; echo \"$var\""])
+        .write_stdin("hello:world")
+        .assert()
+        .success()
+        .stdout("hello\n");
+}
+
+#[test]
+fn test_nchars() {
+    new_cmd!()
+        .args(&["-c", "read -n 3 var; echo \"$var\""])
+        .write_stdin("hello")
+        .assert()
+        .success()
+        .stdout("hel\n");
+}
+
+#[test]
+fn test_nchars_noeof_ignores_delim() {
+    new_cmd!()
+        .args(&["-c", "read -N 5 var; echo \"$var\""])
+        .write_stdin("he\nlo")
+        .assert()
+        .success()
+        .stdout("he\nlo\n");
+}
@@ -0,0 +1,39 @@
+//
+// Copyright (c) 2018, The MesaLock Linux Project Contributors
+// All rights reserved.
+//
+// This work is licensed under the terms of the BSD 3-Clause License.
+// For a copy, see the LICENSE file.
+//
+
+use assert_cmd::prelude::*;
+use std::process::Command;
+
+const NAME: &str = "sh";
+
+#[test]
+fn test_nofile_round_trip() {
+    new_cmd!()
+        .args(&["-c", "ulimit -n 123; ulimit -n"])
+        .assert()
+        .success()
+        .stdout("123\n");
+}
+
+#[test]
+fn test_nofile_default_is_soft() {
+    new_cmd!()
+        .args(&["-c", "ulimit -S -n 123; ulimit -n"])
+        .assert()
+        .success()
+        .stdout("123\n");
+}
+
+#[test]
+fn test_hard_limit_leaves_soft_untouched() {
+    new_cmd!()
+        .args(&["-c", "ulimit -n 123; ulimit -H -n 456; ulimit -n"])
+        .assert()
+        .success()
+        .stdout("123\n");
+}